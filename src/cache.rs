@@ -0,0 +1,148 @@
+//! Reusing a still-valid key across invocations
+//!
+//! The key files on disk are the cache. A per-user lock file in the runtime
+//! directory serialises fetches, so processes started together wait for the
+//! first one and then reuse its key instead of each hitting the proxy.
+
+use anyhow::{Context, Result};
+use std::fs::{self, DirBuilder, File};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use sshproxy_rust::cert::{self, CertValidity};
+use sshproxy_rust::files::cert_path_for;
+
+use crate::metadata::{Metadata, Source};
+
+/// Directory holding the lock files
+fn lock_dir() -> PathBuf {
+    dirs::runtime_dir()
+        .map(|dir| dir.join("sshproxy-rust"))
+        .unwrap_or_else(|| {
+            // SAFETY: getuid has no preconditions and cannot fail
+            let uid = unsafe { libc::getuid() };
+            std::env::temp_dir().join(format!("sshproxy-rust-{}", uid))
+        })
+}
+
+/// Exclusive per-user lock, released when dropped
+pub struct FetchLock {
+    _file: File,
+}
+
+impl FetchLock {
+    /// Block until no other invocation is fetching for this user
+    pub fn acquire(username: &str) -> Result<Self> {
+        if username.is_empty() || username.contains('/') || username.contains("..") {
+            anyhow::bail!("Username '{}' cannot be used to name a lock file", username);
+        }
+        let dir = lock_dir();
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("Failed to create lock directory {}", dir.display()))?;
+        check_lock_dir(&dir)?;
+
+        let path = dir.join(format!("{}.lock", username));
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+
+        // SAFETY: the descriptor stays valid for the lifetime of `file`
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to lock {}", path.display()));
+        }
+        Ok(FetchLock { _file: file })
+    }
+}
+
+/// Fail unless the lock directory is a real directory of ours that nobody
+/// else can write to
+///
+/// In a shared temp directory another user could have created it first, to
+/// hold the lock forever or plant symlinks in it.
+fn check_lock_dir(dir: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(dir)
+        .with_context(|| format!("Failed to inspect lock directory {}", dir.display()))?;
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let euid = unsafe { libc::geteuid() };
+    if !meta.is_dir() || meta.uid() != euid || meta.mode() & 0o022 != 0 {
+        anyhow::bail!(
+            "Lock directory {} is not a directory owned by you (uid {}) and closed to \
+             writes by others; remove it and try again",
+            dir.display(),
+            euid
+        );
+    }
+    Ok(())
+}
+
+/// Validity of the key on disk if it can be reused for at least `min_valid` seconds
+///
+/// A key recorded as fetched from another URL, scope or profile than
+/// `source` is not reused.
+pub fn reusable_key(
+    key_path: &Path,
+    username: &str,
+    source: &Source,
+    min_valid: u64,
+) -> Option<CertValidity> {
+    if !key_path.exists() {
+        return None;
+    }
+    if Metadata::read(key_path).is_some_and(|metadata| !metadata.is_from(source)) {
+        return None;
+    }
+    let cert = cert::read_certificate(&cert_path_for(key_path)).ok()?;
+    // A key issued for someone else is never reused
+    let principals = cert.valid_principals();
+    if !principals.is_empty() && !principals.iter().any(|p| p == username) {
+        return None;
+    }
+    let validity = CertValidity::of(&cert);
+    match validity.remaining(cert::now()) {
+        Some(remaining) if remaining >= min_valid => Some(validity),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn rejects_usernames_leaving_lock_dir() {
+        for username in ["", "../alice", "a/b", ".."] {
+            assert!(FetchLock::acquire(username).is_err(), "{}", username);
+        }
+    }
+
+    #[test]
+    fn lock_dir_must_be_private() {
+        let dir = std::env::temp_dir().join(format!("sshproxy-lock-test-{}", std::process::id()));
+        let locks = dir.join("locks");
+        fs::create_dir_all(&locks).unwrap();
+
+        fs::set_permissions(&locks, fs::Permissions::from_mode(0o700)).unwrap();
+        check_lock_dir(&locks).unwrap();
+
+        fs::set_permissions(&locks, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(check_lock_dir(&locks).is_err());
+
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&locks, &link).unwrap();
+        fs::set_permissions(&locks, fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(check_lock_dir(&link).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+//! Parsing of the OpenSSH certificate issued by sshproxy
+
+use anyhow::{Context, Result};
+use ssh_key::public::KeyData;
+use ssh_key::{Algorithm, Certificate, HashAlg, PublicKey};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Validity window of a certificate, in Unix seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertValidity {
+    pub valid_after: u64,
+    pub valid_before: u64,
+}
+
+impl CertValidity {
+    pub fn of(cert: &Certificate) -> Self {
+        CertValidity {
+            valid_after: cert.valid_after(),
+            valid_before: cert.valid_before(),
+        }
+    }
+
+    /// Seconds left before expiry, `None` if expired or not yet valid
+    pub fn remaining(&self, now: u64) -> Option<u64> {
+        if now < self.valid_after || now >= self.valid_before {
+            return None;
+        }
+        Some(self.valid_before - now)
+    }
+
+    /// Length of the whole validity window in seconds
+    pub fn lifetime(&self) -> u64 {
+        self.valid_before.saturating_sub(self.valid_after)
+    }
+}
+
+/// Extra encoding the certificate can be written in, next to `-cert.pub`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CertFormat {
+    /// The base64 blob of `-cert.pub` alone, without key type or comment
+    Base64,
+    /// The raw wire-format bytes
+    Binary,
+}
+
+impl CertFormat {
+    /// Suffix appended to the key path, like `-cert.pub`
+    pub fn suffix(self) -> &'static str {
+        match self {
+            CertFormat::Base64 => "-cert.b64",
+            CertFormat::Binary => "-cert.bin",
+        }
+    }
+
+    /// Encode `cert`, checking that the result decodes to the same certificate
+    pub fn encode(self, cert: &Certificate) -> Result<Vec<u8>> {
+        let bytes = cert.to_bytes().context("Failed to encode certificate")?;
+        let encoded = match self {
+            CertFormat::Base64 => {
+                let mut text = data_encoding::BASE64.encode(&bytes);
+                text.push('\n');
+                text.into_bytes()
+            }
+            CertFormat::Binary => bytes.clone(),
+        };
+
+        let decoded = match self {
+            CertFormat::Base64 => data_encoding::BASE64
+                .decode(encoded.trim_ascii())
+                .context("Encoded certificate is not valid base64")?,
+            CertFormat::Binary => encoded.clone(),
+        };
+        let reparsed =
+            Certificate::from_bytes(&decoded).context("Encoded certificate does not parse back")?;
+        // Compare the wire form: the comment is not part of it but is part of `==`
+        if reparsed.to_bytes().ok().as_deref() != Some(&bytes[..]) {
+            anyhow::bail!("Encoded certificate does not match the issued one");
+        }
+        Ok(encoded)
+    }
+}
+
+/// Algorithm names accepted in a [`KeyPolicy`]
+pub const KEY_ALGORITHMS: &[&str] = &["rsa", "ed25519", "ecdsa", "dsa", "sk-ecdsa", "sk-ed25519"];
+
+/// Short algorithm name of a key, one of [`KEY_ALGORITHMS`] for known types
+pub fn key_algorithm_name(key: &KeyData) -> String {
+    match key.algorithm() {
+        Algorithm::Rsa { .. } => "rsa".to_string(),
+        Algorithm::Ed25519 => "ed25519".to_string(),
+        Algorithm::Ecdsa { .. } => "ecdsa".to_string(),
+        Algorithm::Dsa => "dsa".to_string(),
+        Algorithm::SkEcdsaSha2NistP256 => "sk-ecdsa".to_string(),
+        Algorithm::SkEd25519 => "sk-ed25519".to_string(),
+        other => other.as_str().to_string(),
+    }
+}
+
+/// Modulus size of an RSA key in bits
+pub fn rsa_bits(key: &KeyData) -> Option<usize> {
+    let modulus = key.rsa()?.n.as_positive_bytes()?;
+    let first = *modulus.first()?;
+    Some(modulus.len() * 8 - first.leading_zeros() as usize)
+}
+
+/// Key types and sizes a site accepts from the server
+#[derive(Debug, Clone, Default)]
+pub struct KeyPolicy {
+    /// Allowed algorithm names; `None` allows any
+    pub allowed_algorithms: Option<Vec<String>>,
+    /// Smallest acceptable RSA modulus
+    pub min_rsa_bits: Option<usize>,
+}
+
+impl KeyPolicy {
+    /// Fail if the issued key falls outside the policy
+    pub fn check(&self, key: &KeyData) -> Result<()> {
+        let name = key_algorithm_name(key);
+        if let Some(allowed) = &self.allowed_algorithms {
+            if !allowed.contains(&name) {
+                anyhow::bail!(
+                    "Server issued a key of type {}, allowed types are: {}",
+                    name,
+                    allowed.join(", ")
+                );
+            }
+        }
+        if let (Some(min), true) = (self.min_rsa_bits, key.is_rsa()) {
+            let bits = rsa_bits(key).context("Failed to read RSA key size")?;
+            if bits < min {
+                anyhow::bail!(
+                    "Server issued a {}-bit RSA key, but at least {} bits are required",
+                    bits,
+                    min
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Trusted CA public keys, read from a file with one OpenSSH public key per line
+pub struct CaBundle {
+    keys: Vec<PublicKey>,
+}
+
+impl CaBundle {
+    /// Read a bundle; blank lines and `#` comments are skipped
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("In CA bundle {}", path.display()))
+    }
+
+    /// Parse bundle text in the same format as [`CaBundle::read`]
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut keys = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = PublicKey::from_openssh(line)
+                .with_context(|| format!("Invalid public key on line {}", number + 1))?;
+            keys.push(key);
+        }
+        if keys.is_empty() {
+            anyhow::bail!("CA bundle contains no keys");
+        }
+        Ok(CaBundle { keys })
+    }
+
+    /// The bundle entry that signed `cert`, once its signature is checked
+    pub fn verify(&self, cert: &Certificate) -> Result<&PublicKey> {
+        let fingerprint = cert.signature_key().fingerprint(HashAlg::Sha256);
+        let ca = self
+            .keys
+            .iter()
+            .find(|key| key.key_data() == cert.signature_key())
+            .with_context(|| {
+                format!(
+                    "Certificate was signed by CA {}, which is not in the CA bundle",
+                    fingerprint
+                )
+            })?;
+        cert.validate_at(now(), [&fingerprint]).map_err(|e| {
+            anyhow::anyhow!(
+                "Certificate signed by CA {} failed validation: {}",
+                fingerprint,
+                e
+            )
+        })?;
+        Ok(ca)
+    }
+}
+
+/// Parse a certificate line (`<type>-cert-v01@openssh.com <base64> [comment]`)
+pub fn parse_certificate(line: &str) -> Result<Certificate> {
+    Certificate::from_openssh(line.trim()).context("Failed to parse SSH certificate")
+}
+
+/// Read and parse a certificate file
+pub fn read_certificate(path: &Path) -> Result<Certificate> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read certificate {}", path.display()))?;
+    parse_certificate(&text)
+}
+
+/// Current Unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Short human form of a number of seconds, e.g. `5h 3m`
+pub fn format_seconds(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    match (days, hours) {
+        (0, 0) if minutes == 0 => format!("{}s", secs),
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+/// Parse a duration like `90`, `30s`, `5m`, `4h`, `1d` or `1h 30m` into seconds
+///
+/// A bare number is seconds. The units are the ones [`format_seconds`] prints,
+/// so its output parses back; `m` is always minutes.
+pub fn parse_duration(text: &str) -> Result<u64> {
+    let text = text.trim();
+    if text.is_empty() {
+        anyhow::bail!("Empty duration");
+    }
+    if text.starts_with('-') {
+        anyhow::bail!("Duration '{}' cannot be negative", text);
+    }
+    if text.contains(['.', ',']) {
+        anyhow::bail!(
+            "Duration '{}' has a fraction, use a smaller unit instead, e.g. 90m for 1.5h",
+            text
+        );
+    }
+    if text.bytes().all(|b| b.is_ascii_digit()) {
+        return text
+            .parse()
+            .with_context(|| format!("Duration '{}' is too large", text));
+    }
+
+    let mut total: u64 = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            anyhow::bail!(
+                "Invalid duration '{}', expected e.g. 30s, 5m, 4h or 1d",
+                text
+            );
+        }
+        let (number, after) = rest.split_at(digits);
+        let unit_len = after
+            .find(|c: char| c.is_ascii_digit() || c.is_whitespace())
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        let scale = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            "" => anyhow::bail!(
+                "Duration '{}' needs a unit after {}: s, m, h or d",
+                text,
+                number
+            ),
+            _ => anyhow::bail!(
+                "Unknown unit '{}' in duration '{}', expected s, m, h or d",
+                unit,
+                text
+            ),
+        };
+        let value: u64 = number
+            .parse()
+            .with_context(|| format!("Duration '{}' is too large", text))?;
+        total = value
+            .checked_mul(scale)
+            .and_then(|secs| total.checked_add(secs))
+            .with_context(|| format!("Duration '{}' is too large", text))?;
+        rest = after.trim_start();
+    }
+    Ok(total)
+}
@@ -0,0 +1,142 @@
+//! Waiting for the system clock to be synchronized before generating a code
+//!
+//! Right after a VM resumes from suspend its clock can be off until NTP
+//! catches up, and every TOTP code generated in that window is rejected.
+
+use anyhow::{Context, Result};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Largest difference to the server's clock still accepted as synchronized
+///
+/// Half a TOTP step, so the generated code is the one the server expects.
+const MAX_OFFSET: Duration = Duration::from_secs(15);
+
+/// Pause between checks
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Whether systemd reports the clock as synchronized by NTP
+#[cfg(target_os = "linux")]
+fn ntp_synchronized() -> bool {
+    std::process::Command::new("timedatectl")
+        .args(["show", "--property=NTPSynchronized", "--value"])
+        .output()
+        .is_ok_and(|output| output.status.success() && output.stdout.trim_ascii() == b"yes")
+}
+
+/// macOS has no equivalent of timedatectl, so the server's clock decides
+#[cfg(target_os = "macos")]
+fn ntp_synchronized() -> bool {
+    false
+}
+
+/// Difference between the local clock and the server's `Date` header
+///
+/// No credentials are sent; any response carrying a `Date` header will do.
+async fn server_offset(url: &str) -> Result<Duration> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let response = client
+        .head(url)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {} to check the clock", url))?;
+    let date = response
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|value| value.to_str().ok())
+        .context("Server response has no Date header to check the clock against")?;
+    let server = httpdate::parse_http_date(date)
+        .with_context(|| format!("Invalid Date header from server: {}", date))?;
+
+    let local = SystemTime::now();
+    Ok(local
+        .duration_since(server)
+        .or_else(|_| server.duration_since(local))
+        .unwrap_or_default())
+}
+
+/// Whether the clock is synchronized right now, and if not by how much it is off
+///
+/// A clock NTP has not confirmed can still be right, e.g. in a container
+/// without timesyncd, so the server's clock has the final say.
+async fn check(url: &str) -> Result<(bool, String)> {
+    if ntp_synchronized() {
+        return Ok((true, String::new()));
+    }
+    let offset = server_offset(url).await?;
+    let state = format!("clock is {}s off from the server", offset.as_secs());
+    Ok((offset <= MAX_OFFSET, state))
+}
+
+/// Return once the clock is synchronized, failing after `timeout`
+///
+/// A check that fails, e.g. while the network is still coming up after a
+/// resume, counts as not synchronized yet; its error is only reported if the
+/// clock is still not synchronized when the timeout passes.
+pub async fn wait_for_sync(url: &str, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut announced = false;
+    loop {
+        let state = match check(url).await {
+            Ok((true, _)) => return Ok(()),
+            Ok((false, state)) => Ok(state),
+            Err(err) => Err(err),
+        };
+        if Instant::now() + POLL_INTERVAL > deadline {
+            let reason = match state {
+                Ok(state) => state,
+                Err(err) => format!("last check failed: {:#}", err),
+            };
+            anyhow::bail!(
+                "System clock not synchronized after {}s ({}); TOTP codes would be rejected",
+                timeout.as_secs(),
+                reason
+            );
+        }
+        if !announced {
+            match &state {
+                Ok(state) => eprintln!(
+                    "{}Waiting for the system clock to synchronize: {}",
+                    crate::tag(),
+                    state
+                ),
+                Err(_) => eprintln!(
+                    "{}Waiting for the system clock to synchronize",
+                    crate::tag()
+                ),
+            }
+            announced = true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_polling_while_server_unreachable() {
+        if ntp_synchronized() {
+            return;
+        }
+        // Nothing listens on port 1
+        let started = Instant::now();
+        let err = wait_for_sync("http://127.0.0.1:1", Duration::from_secs(3))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            started.elapsed() >= POLL_INTERVAL,
+            "gave up after the first check"
+        );
+        assert!(err.contains("not synchronized after 3s"), "{}", err);
+        assert!(
+            err.contains("last check failed: Failed to reach"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! Optional user configuration read from `~/.config/sshproxy/config.toml`
+//!
+//! Fragments in `config.d/*.toml` next to it are merged on top. Every setting
+//! is optional; no files at all means defaults everywhere.
+
+use anyhow::{Context, Result};
+use reqwest::header::HeaderName;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::{env, fs};
+
+use sshproxy_rust::cert::{self, KeyPolicy, KEY_ALGORITHMS};
+use sshproxy_rust::credentials::KeychainAccessibility;
+use sshproxy_rust::tls::{self, TlsVersion};
+use sshproxy_rust::totp::SecretEncoding;
+
+use crate::template;
+
+/// Default sshproxy service
+pub const DEFAULT_URL: &str = "https://sshproxy.nersc.gov";
+
+/// Default key scope
+pub const DEFAULT_SCOPE: &str = "default";
+
+/// Placeholders available in `success_template`
+pub const SUCCESS_PLACEHOLDERS: &[&str] = &["key_path", "valid_until", "username"];
+
+/// Default success line, matching the historical output
+pub const DEFAULT_SUCCESS_TEMPLATE: &str = "Successfully obtained ssh key: {key_path}";
+
+/// Response to a policy violation that does not have to be fatal
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolicyAction {
+    Warn,
+    #[default]
+    Fail,
+}
+
+/// Named settings selected with `--profile` or `SSHPROXY_PROFILE`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Replaces the top-level `url`
+    pub url: Option<String>,
+
+    /// Replaces the top-level `scope`
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Format of the line printed after a successful fetch
+    pub success_template: Option<String>,
+
+    /// Encoding of the stored TOTP seed (base32, hex or base64)
+    pub secret_encoding: Option<SecretEncoding>,
+
+    /// Base URL of the sshproxy service, overridden by `SSHPROXY_URL`
+    pub url: Option<String>,
+
+    /// Key scope to request, overridden by `SSHPROXY_SCOPE`
+    pub scope: Option<String>,
+
+    /// When stored items are readable on macOS; ignored on Linux
+    pub keychain_accessibility: Option<KeychainAccessibility>,
+
+    /// Key algorithms accepted from the server, e.g. `["ed25519", "rsa"]`
+    pub allowed_key_algorithms: Option<Vec<String>>,
+
+    /// Smallest RSA key accepted from the server, in bits
+    pub min_rsa_bits: Option<usize>,
+
+    /// Longest certificate validity accepted from the server, in seconds or
+    /// as a duration like `"2d"`
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub max_cert_validity: Option<u64>,
+
+    /// What a certificate valid for longer than `max_cert_validity` causes
+    pub max_cert_validity_action: Option<PolicyAction>,
+
+    /// SHA-256 of the server's TLS public key (SPKI), base64
+    pub tls_pin: Option<String>,
+
+    /// Oldest TLS version accepted from the server, `"1.2"` or `"1.3"`
+    pub min_tls_version: Option<TlsVersion>,
+
+    /// Offer HTTP/2 to the server, falling back to HTTP/1.1 if it declines
+    pub allow_http2: Option<bool>,
+
+    /// Header to send the credential in instead of `Authorization`
+    pub auth_header: Option<String>,
+
+    /// Scheme in front of the credential instead of `Basic`, e.g. `Bearer`
+    pub auth_scheme: Option<String>,
+
+    /// Named profiles, as `[profiles.<name>]` tables
+    pub profiles: BTreeMap<String, Profile>,
+
+    /// Profile applied by [`Config::select_profile`], if any
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+impl Config {
+    /// Location of the configuration file
+    pub fn path() -> Result<PathBuf> {
+        let home = dirs::home_dir().context("Could not determine home directory")?;
+        Ok(home.join(".config").join("sshproxy").join("config.toml"))
+    }
+
+    /// Directory of fragments merged on top of the configuration file
+    pub fn fragment_dir() -> Result<PathBuf> {
+        Ok(Self::path()?.with_file_name("config.d"))
+    }
+
+    /// `*.toml` files in the fragment directory, in lexical order
+    pub fn fragments() -> Result<Vec<PathBuf>> {
+        let dir = Self::fragment_dir()?;
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read config directory {}", dir.display()))
+            }
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("Failed to read config directory {}", dir.display()))?
+                .path();
+            if path.extension().is_some_and(|ext| ext == "toml") && path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Load and validate the configuration file and its fragments, if any
+    ///
+    /// Each fragment replaces the settings it contains, later ones winning;
+    /// tables such as `[profiles.*]` are merged key by key, lists are
+    /// replaced whole rather than appended to.
+    pub fn load() -> Result<Self> {
+        let mut merged = toml::Table::new();
+        let files = std::iter::once(Self::path()?).chain(Self::fragments()?);
+        for path in files {
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to read config file {}", path.display()))
+                }
+            };
+            // Checked on its own first, so errors name the file they are in
+            Self::parse(&text).with_context(|| format!("In config file {}", path.display()))?;
+            let table: toml::Table = toml::from_str(&text)?;
+            merge_tables(&mut merged, table);
+        }
+
+        let config: Config = merged.try_into().context("Failed to merge config files")?;
+        config.validate().context("Invalid config")?;
+        Ok(config)
+    }
+
+    /// Parse and validate configuration text
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: Config = toml::from_str(text).context("Failed to parse config")?;
+        config.validate().context("Invalid config")?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(success_template) = &self.success_template {
+            template::check_placeholders(success_template, SUCCESS_PLACEHOLDERS)
+                .context("Invalid success_template")?;
+        }
+        self.tls_pin()?;
+        self.auth_header()?;
+        if let Some(scheme) = &self.auth_scheme {
+            // An HTTP token: no spaces, separators or control characters
+            if scheme.is_empty()
+                || !scheme
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+            {
+                anyhow::bail!("Invalid auth_scheme '{}'", scheme);
+            }
+        }
+        for name in self.allowed_key_algorithms.iter().flatten() {
+            if !KEY_ALGORITHMS.contains(&name.as_str()) {
+                anyhow::bail!(
+                    "Unknown key algorithm '{}' in allowed_key_algorithms, expected one of: {}",
+                    name,
+                    KEY_ALGORITHMS.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the profile named by `flag`, or else by `SSHPROXY_PROFILE`
+    ///
+    /// `SSHPROXY_URL` and `SSHPROXY_SCOPE` still win over the profile.
+    pub fn select_profile(&mut self, flag: Option<&str>) -> Result<()> {
+        let (name, source) = match flag {
+            Some(name) => (name.to_string(), "--profile"),
+            None => match env::var("SSHPROXY_PROFILE") {
+                Ok(name) if !name.is_empty() => (name, "SSHPROXY_PROFILE"),
+                _ => return Ok(()),
+            },
+        };
+        let Some(profile) = self.profiles.get(&name) else {
+            let known = if self.profiles.is_empty() {
+                "no profiles are configured".to_string()
+            } else {
+                let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                format!("configured profiles: {}", names.join(", "))
+            };
+            anyhow::bail!("Unknown profile '{}' from {} ({})", name, source, known);
+        };
+        if let Some(url) = &profile.url {
+            self.url = Some(url.clone());
+        }
+        if let Some(scope) = &profile.scope {
+            self.scope = Some(scope.clone());
+        }
+        self.profile = Some(name);
+        Ok(())
+    }
+
+    /// Base URL of the sshproxy service
+    pub fn url(&self) -> String {
+        env::var("SSHPROXY_URL")
+            .ok()
+            .or_else(|| self.url.clone())
+            .unwrap_or_else(|| DEFAULT_URL.to_string())
+    }
+
+    /// Key scope to request
+    pub fn scope(&self) -> String {
+        env::var("SSHPROXY_SCOPE")
+            .ok()
+            .or_else(|| self.scope.clone())
+            .unwrap_or_else(|| DEFAULT_SCOPE.to_string())
+    }
+
+    /// Fully resolved `create_pair` endpoint
+    pub fn endpoint(&self) -> Result<String> {
+        endpoint_for(&self.url(), &self.scope())
+    }
+
+    /// Policy the issued key has to satisfy
+    pub fn key_policy(&self) -> KeyPolicy {
+        KeyPolicy {
+            allowed_algorithms: self.allowed_key_algorithms.clone(),
+            min_rsa_bits: self.min_rsa_bits,
+        }
+    }
+
+    /// Pinned server public key, if configured
+    pub fn tls_pin(&self) -> Result<Option<[u8; 32]>> {
+        self.tls_pin.as_deref().map(tls::parse_pin).transpose()
+    }
+
+    /// Header to send the credential in, if not `Authorization`
+    pub fn auth_header(&self) -> Result<Option<HeaderName>> {
+        self.auth_header
+            .as_deref()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("Invalid auth_header '{}'", name))
+            })
+            .transpose()
+    }
+
+    /// Template for the success line
+    pub fn success_template(&self) -> &str {
+        self.success_template
+            .as_deref()
+            .unwrap_or(DEFAULT_SUCCESS_TEMPLATE)
+    }
+}
+
+/// A duration given as seconds or as a string for [`cert::parse_duration`]
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Seconds(u64),
+        Text(String),
+    }
+    match Value::deserialize(deserializer)? {
+        Value::Seconds(secs) => Ok(Some(secs)),
+        Value::Text(text) => cert::parse_duration(&text)
+            .map(Some)
+            .map_err(|e| serde::de::Error::custom(format!("{:#}", e))),
+    }
+}
+
+/// Merge `from` into `into`, recursing into tables present in both
+fn merge_tables(into: &mut toml::Table, from: toml::Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(table)) => {
+                merge_tables(existing, table)
+            }
+            (_, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+/// `create_pair` endpoint for a base URL and scope
+pub fn endpoint_for(url: &str, scope: &str) -> Result<String> {
+    let parsed = reqwest::Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+    let loopback = matches!(parsed.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    if parsed.scheme() != "https" && !(parsed.scheme() == "http" && loopback) {
+        anyhow::bail!("URL {} must use https", url);
+    }
+
+    if scope.is_empty() || scope.contains('/') {
+        anyhow::bail!("Invalid scope '{}'", scope);
+    }
+
+    Ok(format!(
+        "{}/create_pair/{}/",
+        url.trim_end_matches('/'),
+        scope
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragments_merge_profiles() {
+        let mut merged = toml::Table::new();
+        for text in [
+            "scope = \"default\"\n[profiles.one]\nscope = \"a\"\nurl = \"https://one.example.org\"\n",
+            "[profiles.two]\nscope = \"b\"\n",
+            "allowed_key_algorithms = [\"ed25519\"]\n[profiles.one]\nscope = \"c\"\n",
+        ] {
+            merge_tables(&mut merged, toml::from_str(text).unwrap());
+        }
+        let config: Config = merged.try_into().unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.scope.as_deref(), Some("default"));
+        assert_eq!(
+            config.allowed_key_algorithms.as_deref(),
+            Some(&["ed25519".to_string()][..])
+        );
+        assert_eq!(config.profiles.len(), 2);
+        let one = &config.profiles["one"];
+        assert_eq!(one.scope.as_deref(), Some("c"));
+        assert_eq!(one.url.as_deref(), Some("https://one.example.org"));
+        assert_eq!(config.profiles["two"].scope.as_deref(), Some("b"));
+    }
+}
@@ -0,0 +1,31 @@
+//! Logging in to a real host with the fetched key, for `--verify-connect`
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Run `true` on `host` with only this key and certificate offered
+///
+/// `IdentitiesOnly` keeps keys from the agent or `~/.ssh/config` out of it,
+/// so success means this certificate was accepted. `BatchMode` turns any
+/// password or host key prompt into a failure instead of waiting for input.
+pub fn verify(host: &str, key: &Path, cert: &Path) -> Result<()> {
+    let output = Command::new("ssh")
+        .args(["-o", "BatchMode=yes", "-o", "ConnectTimeout=5"])
+        .args(["-o", "IdentitiesOnly=yes", "-i"])
+        .arg(key)
+        .arg(format!("-oCertificateFile={}", cert.display()))
+        .args(["--", host, "true"])
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run ssh")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr
+            .lines()
+            .rfind(|line| !line.trim().is_empty())
+            .unwrap_or("no error output");
+        anyhow::bail!("ssh to {} failed: {}", host, reason.trim());
+    }
+    Ok(())
+}
@@ -0,0 +1,310 @@
+//! Password and TOTP secret storage in the system credential store
+//!
+//! macOS uses the login keychain, Linux the kernel keyring. Both items are
+//! stored per username: the password under `NERSC`, the secret under
+//! `NERSC_SECRET`.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+#[cfg(target_os = "macos")]
+use security_framework::passwords::get_generic_password;
+
+#[cfg(target_os = "linux")]
+use keyring::Entry;
+
+/// Service name of the stored password; the secret uses `NERSC_SECRET`
+pub const SERVICE_NAME: &str = "NERSC";
+
+/// Credential store used on this platform
+#[cfg(target_os = "macos")]
+pub const BACKEND: &str = "macOS login keychain";
+
+/// Credential store used on this platform
+#[cfg(target_os = "linux")]
+pub const BACKEND: &str = "Linux kernel keyring";
+
+/// A stored password or secret, described without its value
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StoredItem {
+    pub username: String,
+    /// `NERSC` for the password, `NERSC_SECRET` for the TOTP secret
+    pub service: String,
+    /// Last change as reported by the store; the kernel keyring keeps none
+    pub modified: Option<String>,
+}
+
+/// macOS keychain accessibility class (`kSecAttrAccessible`) for stored items
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeychainAccessibility {
+    WhenUnlocked,
+    #[default]
+    WhenUnlockedThisDeviceOnly,
+    AfterFirstUnlock,
+    AfterFirstUnlockThisDeviceOnly,
+    WhenPasscodeSetThisDeviceOnly,
+}
+
+#[cfg(target_os = "macos")]
+impl KeychainAccessibility {
+    /// Matching security-framework protection mode
+    pub fn protection_mode(self) -> security_framework::access_control::ProtectionMode {
+        use security_framework::access_control::ProtectionMode;
+        match self {
+            KeychainAccessibility::WhenUnlocked => ProtectionMode::AccessibleWhenUnlocked,
+            KeychainAccessibility::WhenUnlockedThisDeviceOnly => {
+                ProtectionMode::AccessibleWhenUnlockedThisDeviceOnly
+            }
+            KeychainAccessibility::AfterFirstUnlock => ProtectionMode::AccessibleAfterFirstUnlock,
+            KeychainAccessibility::AfterFirstUnlockThisDeviceOnly => {
+                ProtectionMode::AccessibleAfterFirstUnlockThisDeviceOnly
+            }
+            KeychainAccessibility::WhenPasscodeSetThisDeviceOnly => {
+                ProtectionMode::AccessibleWhenPasscodeSetThisDeviceOnly
+            }
+        }
+    }
+}
+
+/// Store a keychain item with the given accessibility
+///
+/// An existing item is removed first: updating it in place would keep its
+/// old accessibility class. Its value is read beforehand and stored again,
+/// with the default accessibility, if the new item cannot be added, so a
+/// failed update does not lose the stored credential.
+#[cfg(target_os = "macos")]
+fn set_keychain_item(
+    service: &str,
+    username: &str,
+    data: &[u8],
+    accessibility: KeychainAccessibility,
+) -> security_framework::base::Result<()> {
+    use security_framework::access_control::SecAccessControl;
+    use security_framework::passwords::{
+        delete_generic_password, set_generic_password, set_generic_password_options,
+        PasswordOptions,
+    };
+    use zeroize::Zeroizing;
+    // errSecItemNotFound: nothing stored yet
+    const ERR_SEC_ITEM_NOT_FOUND: i32 = -25300;
+
+    let old = match get_generic_password(service, username) {
+        Ok(old) => Some(Zeroizing::new(old)),
+        Err(e) if e.code() == ERR_SEC_ITEM_NOT_FOUND => None,
+        Err(e) => return Err(e),
+    };
+    if old.is_some() {
+        delete_generic_password(service, username)?;
+    }
+
+    let added = SecAccessControl::create_with_protection(Some(accessibility.protection_mode()), 0)
+        .and_then(|access| {
+            let mut options = PasswordOptions::new_generic_password(service, username);
+            options.set_access_control(access);
+            set_generic_password_options(data, options)
+        });
+    if let (Err(_), Some(old)) = (&added, &old) {
+        // The add error is the one worth reporting
+        let _ = set_generic_password(service, username, old);
+    }
+    added
+}
+
+/// NERSC passwords expire every year.
+#[cfg(target_os = "macos")]
+pub fn update_password(
+    username: &str,
+    password: &str,
+    accessibility: KeychainAccessibility,
+) -> Result<()> {
+    // save password
+    set_keychain_item(SERVICE_NAME, username, password.as_bytes(), accessibility)
+        .context("Failed to save password to keychain")?;
+    Ok(())
+}
+/// usually totp secrets do not expire
+#[cfg(target_os = "macos")]
+pub fn update_secret(
+    username: &str,
+    otp_secret: &str,
+    accessibility: KeychainAccessibility,
+) -> Result<()> {
+    // save otp secret
+    let service = format!("{}_SECRET", SERVICE_NAME);
+    set_keychain_item(&service, username, otp_secret.as_bytes(), accessibility)
+        .context("Failed to save OTP secret to keychain")?;
+    Ok(())
+}
+
+/// Turn a keychain read error into a clear message when the keychain is locked
+#[cfg(target_os = "macos")]
+fn keychain_read_error(err: security_framework::base::Error, what: &str) -> anyhow::Error {
+    // errSecInteractionNotAllowed: the keychain is locked and no UI may be shown
+    const ERR_SEC_INTERACTION_NOT_ALLOWED: i32 = -25308;
+
+    if err.code() == ERR_SEC_INTERACTION_NOT_ALLOWED {
+        anyhow::Error::new(crate::error::Fatal::KeychainLocked)
+    } else {
+        anyhow::Error::new(err).context(format!("Failed to retrieve {} from keychain", what))
+    }
+}
+
+/// Retrieve password from macOS Keychain
+#[cfg(target_os = "macos")]
+pub fn get_password(username: &str) -> Result<String> {
+    let password = get_generic_password(SERVICE_NAME, username)
+        .map_err(|e| keychain_read_error(e, "password"))?;
+    Ok(String::from_utf8(password.to_vec())?)
+}
+
+/// Retrieve OTP secret from macOS Keychain exactly as stored
+#[cfg(target_os = "macos")]
+pub fn get_otp_secret_untrimmed(username: &str) -> Result<String> {
+    let service = format!("{}_SECRET", SERVICE_NAME);
+    let secret = get_generic_password(&service, username)
+        .map_err(|e| keychain_read_error(e, "OTP secret"))?;
+    Ok(String::from_utf8(secret.to_vec())?)
+}
+
+/// Stored passwords and secrets of every username, sorted, values not read
+///
+/// Only the item attributes are requested, so the keychain never returns
+/// the stored data.
+#[cfg(target_os = "macos")]
+pub fn list_items() -> Result<Vec<StoredItem>> {
+    use security_framework::item::{ItemClass, ItemSearchOptions, Limit};
+    // errSecItemNotFound: nothing stored under this service
+    const ERR_SEC_ITEM_NOT_FOUND: i32 = -25300;
+
+    let mut items = Vec::new();
+    for service in [SERVICE_NAME.to_string(), format!("{}_SECRET", SERVICE_NAME)] {
+        let results = match ItemSearchOptions::new()
+            .class(ItemClass::generic_password())
+            .service(&service)
+            .load_attributes(true)
+            .limit(Limit::All)
+            .search()
+        {
+            Ok(results) => results,
+            Err(e) if e.code() == ERR_SEC_ITEM_NOT_FOUND => continue,
+            Err(e) => return Err(keychain_read_error(e, "the list of stored items")),
+        };
+        for attributes in results.iter().filter_map(|result| result.simplify_dict()) {
+            if let Some(username) = attributes.get("acct") {
+                items.push(StoredItem {
+                    username: username.clone(),
+                    service: service.clone(),
+                    modified: attributes.get("mdat").cloned(),
+                });
+            }
+        }
+    }
+    items.sort();
+    Ok(items)
+}
+
+/// NERSC passwords expire every year.
+// The kernel keyring has no accessibility classes, so that setting is ignored
+#[cfg(target_os = "linux")]
+pub fn update_password(
+    username: &str,
+    password: &str,
+    _accessibility: KeychainAccessibility,
+) -> Result<()> {
+    let entry = Entry::new(SERVICE_NAME, username).context("Failed to create keyring entry")?;
+    entry
+        .set_password(password)
+        .context("Failed to save password to credential storage")?;
+    Ok(())
+}
+
+/// usually totp secrets do not expire
+#[cfg(target_os = "linux")]
+pub fn update_secret(
+    username: &str,
+    otp_secret: &str,
+    _accessibility: KeychainAccessibility,
+) -> Result<()> {
+    let service = format!("{}_SECRET", SERVICE_NAME);
+    let entry = Entry::new(&service, username).context("Failed to create keyring entry")?;
+    entry
+        .set_password(otp_secret)
+        .context("Failed to save OTP secret to credential storage")?;
+    Ok(())
+}
+
+/// Retrieve password from credential storage
+#[cfg(target_os = "linux")]
+pub fn get_password(username: &str) -> Result<String> {
+    let entry = Entry::new(SERVICE_NAME, username).context("Failed to create keyring entry")?;
+    entry
+        .get_password()
+        .context("Failed to retrieve password from credential storage")
+}
+
+/// Retrieve OTP secret from credential storage exactly as stored
+#[cfg(target_os = "linux")]
+pub fn get_otp_secret_untrimmed(username: &str) -> Result<String> {
+    let service = format!("{}_SECRET", SERVICE_NAME);
+    let entry = Entry::new(&service, username).context("Failed to create keyring entry")?;
+    entry
+        .get_password()
+        .context("Failed to retrieve OTP secret from credential storage")
+}
+
+/// Stored passwords and secrets of every username, sorted, values not read
+///
+/// Lists the keys linked into the session and persistent keyrings whose
+/// description keyring-rs gave them, `keyring-rs:<username>@<service>`.
+#[cfg(target_os = "linux")]
+pub fn list_items() -> Result<Vec<StoredItem>> {
+    use linux_keyutils::{KeyRing, KeyRingIdentifier};
+    // Keys read per keyring; far more than one machine has users
+    const MAX_LINKS: usize = 4096;
+
+    let secret_service = format!("{}_SECRET", SERVICE_NAME);
+    let session = KeyRing::from_special_id(KeyRingIdentifier::Session, false)
+        .context("Failed to open the session keyring")?;
+    let rings =
+        std::iter::once(session).chain(KeyRing::get_persistent(KeyRingIdentifier::Session).ok());
+
+    let mut items = Vec::new();
+    for ring in rings {
+        let links = ring
+            .get_links(MAX_LINKS)
+            .context("Failed to list keyring contents")?;
+        for key in links.iter().filter_map(|link| link.as_key()) {
+            let Ok(metadata) = key.metadata() else {
+                continue;
+            };
+            let Some(entry) = metadata.get_description().strip_prefix("keyring-rs:") else {
+                continue;
+            };
+            let Some((username, service)) = entry.rsplit_once('@') else {
+                continue;
+            };
+            if service == SERVICE_NAME || service == secret_service {
+                items.push(StoredItem {
+                    username: username.to_string(),
+                    service: service.to_string(),
+                    modified: None,
+                });
+            }
+        }
+    }
+    // A key linked into both keyrings is listed once
+    items.sort();
+    items.dedup();
+    Ok(items)
+}
+
+/// Retrieve OTP secret without surrounding whitespace
+///
+/// Other tools, and older versions of this one, may have stored the secret
+/// with a trailing newline, which no encoding decodes.
+/// `sshproxy-rust clean-secret` stores the trimmed value for good.
+pub fn get_otp_secret(username: &str) -> Result<String> {
+    let secret = get_otp_secret_untrimmed(username)?;
+    Ok(secret.trim().to_string())
+}
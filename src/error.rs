@@ -0,0 +1,77 @@
+//! Failures that get a dedicated process exit code
+
+use std::fmt;
+use std::panic::PanicHookInfo;
+use std::path::PathBuf;
+
+/// Errors that callers may want to tell apart by exit code
+#[derive(Debug)]
+pub enum Fatal {
+    /// The macOS login keychain is locked and cannot be read
+    KeychainLocked,
+    /// The directory the key would be written to is on a read-only filesystem
+    ReadOnlyOutput(PathBuf),
+    /// `status` found a certificate that has expired this many seconds ago
+    KeyExpired { cert: PathBuf, ago: u64 },
+    /// `status` found no certificate at this path
+    KeyMissing(PathBuf),
+}
+
+impl Fatal {
+    /// Exit code reported for this failure; 1 is used for everything else
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Fatal::KeychainLocked => 3,
+            Fatal::ReadOnlyOutput(_) => 4,
+            Fatal::KeyExpired { .. } => 5,
+            Fatal::KeyMissing(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fatal::KeychainLocked => write!(f, "keychain is locked; unlock it and retry"),
+            Fatal::ReadOnlyOutput(dir) => write!(
+                f,
+                "output location {} is read-only; use --output to a writable path",
+                dir.display()
+            ),
+            Fatal::KeyExpired { cert, ago } => write!(
+                f,
+                "certificate {} expired {} ago; run sshproxy-rust to fetch a new one",
+                cert.display(),
+                crate::cert::format_seconds(*ago)
+            ),
+            Fatal::KeyMissing(cert) => write!(
+                f,
+                "no certificate at {}; run sshproxy-rust to fetch one",
+                cert.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+/// Find a `Fatal` anywhere in the error chain
+pub fn find_fatal(err: &anyhow::Error) -> Option<&Fatal> {
+    err.chain().find_map(|cause| cause.downcast_ref::<Fatal>())
+}
+
+/// Error line for a panic, leaving out its message
+///
+/// A panic message or its formatted arguments could contain the password,
+/// the OTP or key material, so only where it happened is reported.
+pub fn panic_report(info: &PanicHookInfo) -> String {
+    let location = info
+        .location()
+        .map(|l| format!(" at {}:{}", l.file(), l.line()))
+        .unwrap_or_default();
+    format!(
+        "Error: internal error{}; details are withheld as they may contain credentials. \
+         Please report this at https://github.com/aryabhatt/sshproxy-rust/issues",
+        location
+    )
+}
@@ -0,0 +1,597 @@
+//! Writing the key, certificate and public key to disk
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::owner::Owner;
+
+/// Runs of a read-only ssh-keygen call before its failure is reported
+const KEYGEN_ATTEMPTS: u32 = 3;
+
+/// Pause before running ssh-keygen again
+const KEYGEN_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// What to do when the `.pub` on disk is stale, from neither the current nor the new key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PubConflict {
+    /// Replace it, with a notice on stderr
+    #[default]
+    Overwrite,
+    /// Leave the existing file as it is
+    Keep,
+    /// Fail before any file is replaced
+    Error,
+}
+
+/// Paths of the files belonging to a key
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedFiles {
+    pub key: PathBuf,
+    pub cert: PathBuf,
+    pub public: PathBuf,
+}
+
+impl SavedFiles {
+    pub fn for_key(key_path: &Path) -> Self {
+        SavedFiles {
+            key: key_path.to_path_buf(),
+            cert: cert_path_for(key_path),
+            public: pub_path_for(key_path),
+        }
+    }
+}
+
+/// Certificate path that ssh picks up automatically for a key
+pub fn cert_path_for(key_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}-cert.pub", key_path.display()))
+}
+
+/// Public key path for a key
+pub fn pub_path_for(key_path: &Path) -> PathBuf {
+    key_path.with_extension("pub")
+}
+
+/// Write a file readable only by its owner, created with 600 permissions
+pub fn write_private_file(path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies on creation, so tighten an existing file too
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(content)?;
+    Ok(())
+}
+
+/// Create a new directory under the system temp dir that only the owner can enter
+///
+/// Creation fails rather than reusing a directory that already exists, so
+/// nobody else can have prepared it. Removing it is up to the caller.
+pub fn create_private_dir(prefix: &str) -> Result<PathBuf> {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let path = std::env::temp_dir().join(format!("{}-{}-{}", prefix, std::process::id(), nanos));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&path)
+        .with_context(|| format!("Failed to create directory {}", path.display()))?;
+    Ok(path)
+}
+
+/// A file written next to its destination and renamed into place on commit
+///
+/// Until then the destination is untouched; the temporary file is removed
+/// if the staged file is dropped without committing.
+struct Staged {
+    tmp: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+impl Staged {
+    /// Stage `content` for `dest`, owned by `owner` if given
+    ///
+    /// The owner is set on the open file, before anyone else can reach it
+    /// under its final name.
+    fn write(dest: &Path, content: &[u8], mode: u32, owner: Option<Owner>) -> Result<Self> {
+        let tmp = PathBuf::from(format!("{}.tmp.{}", dest.display(), std::process::id()));
+        // Left over from an interrupted run with the same pid
+        let _ = fs::remove_file(&tmp);
+        let staged = Staged {
+            tmp,
+            dest: dest.to_path_buf(),
+            committed: false,
+        };
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&staged.tmp)
+            .map_err(|e| write_error(e, dest))?;
+        file.write_all(content)
+            .and_then(|()| file.sync_all())
+            .map_err(|e| write_error(e, dest))?;
+        if let Some(owner) = owner {
+            owner.apply_to_file(&file, dest)?;
+        }
+        Ok(staged)
+    }
+
+    fn commit(mut self) -> Result<()> {
+        fs::rename(&self.tmp, &self.dest)
+            .with_context(|| format!("Failed to replace {}", self.dest.display()))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Replace `dest` with `content` through a staged copy, owned by `owner` if
+/// given
+///
+/// `dest` itself is never opened, so a symlink planted there is replaced
+/// rather than followed.
+pub fn write_staged(dest: &Path, content: &[u8], mode: u32, owner: Option<Owner>) -> Result<()> {
+    Staged::write(dest, content, mode, owner)?.commit()
+}
+
+/// The directory a key would be written to
+fn output_dir(key_path: &Path) -> &Path {
+    match key_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Create the key's directory with mode 700 and any missing parents with 755
+///
+/// Returns whether the key's directory itself had to be created.
+pub fn create_dirs(key_path: &Path) -> Result<bool> {
+    let dir = output_dir(key_path);
+    let missing: Vec<&Path> = dir
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .collect();
+    let created = !missing.is_empty();
+    for path in missing.into_iter().rev() {
+        let mode = if path == dir { 0o700 } else { 0o755 };
+        match fs::DirBuilder::new().mode(mode).create(path) {
+            Ok(()) => {}
+            // Created by a run in parallel in the meantime
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) if e.kind() == io::ErrorKind::ReadOnlyFilesystem => {
+                return Err(crate::error::Fatal::ReadOnlyOutput(path.to_path_buf()).into())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()))
+            }
+        }
+    }
+    Ok(created)
+}
+
+/// Check that the key's directory can be written, before anything is fetched
+///
+/// Creates and removes a probe file, which also catches read-only mounts
+/// that permission bits don't show.
+pub fn check_writable(key_path: &Path) -> Result<()> {
+    let dir = output_dir(key_path);
+    let probe = dir.join(format!(".sshproxy-write-test.{}", std::process::id()));
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&probe)
+    {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::ReadOnlyFilesystem => {
+            Err(crate::error::Fatal::ReadOnlyOutput(dir.to_path_buf()).into())
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Cannot write to {}", dir.display())),
+    }
+}
+
+/// Name a full disk or exhausted quota explicitly, they are the usual cause
+fn write_error(err: io::Error, dest: &Path) -> anyhow::Error {
+    match err.kind() {
+        io::ErrorKind::ReadOnlyFilesystem => {
+            crate::error::Fatal::ReadOnlyOutput(output_dir(dest).to_path_buf()).into()
+        }
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => anyhow::anyhow!(
+            "No space left to write {} ({}); the existing file was left unchanged",
+            dest.display(),
+            err
+        ),
+        _ => anyhow::Error::new(err).context(format!("Failed to write {}", dest.display())),
+    }
+}
+
+/// The one public key line in `ssh-keygen -y` output, skipping stray warnings
+fn public_key_line(stdout: &[u8]) -> Result<String> {
+    let text = String::from_utf8_lossy(stdout);
+    let mut keys = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| ssh_key::PublicKey::from_openssh(line).is_ok());
+    match (keys.next(), keys.next()) {
+        (Some(key), None) => Ok(format!("{}\n", key)),
+        (None, _) => anyhow::bail!(
+            "ssh-keygen did not output a valid public key: {}",
+            text.trim()
+        ),
+        (Some(_), Some(_)) => anyhow::bail!("ssh-keygen output more than one public key"),
+    }
+}
+
+/// Fingerprint of a stale public key at `path`, one for neither key in play
+///
+/// The `.pub` of the key being replaced, recognised by the certificate next
+/// to it, is routinely replaced and not stale; neither is one that already
+/// holds the new key. Only keys are compared, not comments. A file that
+/// cannot be read as a public key counts as stale; a missing one does not.
+fn stale_public_key(path: &Path, cert_path: &Path, new_line: &str) -> Option<String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(_) => return Some("unreadable".to_string()),
+    };
+    let new = ssh_key::PublicKey::from_openssh(new_line.trim()).ok()?;
+    let old = match ssh_key::PublicKey::from_openssh(text.trim()) {
+        Ok(old) => old,
+        Err(_) => return Some("not a valid public key".to_string()),
+    };
+    let current = crate::cert::read_certificate(cert_path).ok();
+    if old.key_data() == new.key_data()
+        || current.is_some_and(|cert| cert.public_key() == old.key_data())
+    {
+        return None;
+    }
+    Some(old.fingerprint(ssh_key::HashAlg::Sha256).to_string())
+}
+
+/// Calls to ssh-keygen so far, numbering the retry log ids
+static KEYGEN_CALLS: AtomicU32 = AtomicU32::new(0);
+
+/// Run ssh-keygen and return its stdout, retrying a failed run
+///
+/// Only for calls that just read their input file, so running them again is
+/// harmless. A missing ssh-keygen is reported right away; any other failure
+/// is reported with the stderr of the last attempt.
+///
+/// Once an attempt fails, every attempt of the call is passed to `log` with
+/// the same id, its number, outcome and time taken, followed by a summary.
+/// The arguments are only paths and are not logged.
+fn ssh_keygen(args: &[&OsStr], log: &dyn Fn(&str)) -> Result<Vec<u8>> {
+    let id = format!(
+        "{:x}-{}",
+        std::process::id(),
+        KEYGEN_CALLS.fetch_add(1, Ordering::Relaxed) + 1
+    );
+    let started = Instant::now();
+    let mut attempt = 1;
+    loop {
+        let attempt_started = Instant::now();
+        let failure = match std::process::Command::new("ssh-keygen").args(args).output() {
+            Ok(output) if output.status.success() => {
+                if attempt > 1 {
+                    log(&format!(
+                        "ssh-keygen [{}] attempt {}/{} succeeded in {:.1}s",
+                        id,
+                        attempt,
+                        KEYGEN_ATTEMPTS,
+                        attempt_started.elapsed().as_secs_f64()
+                    ));
+                    log(&format!(
+                        "ssh-keygen [{}] succeeded on attempt {} after {:.1}s",
+                        id,
+                        attempt,
+                        started.elapsed().as_secs_f64()
+                    ));
+                }
+                return Ok(output.stdout);
+            }
+            Ok(output) => format!(
+                "{}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(e).context("ssh-keygen not found")
+            }
+            Err(e) => e.to_string(),
+        };
+        log(&format!(
+            "ssh-keygen [{}] attempt {}/{} failed in {:.1}s: {}",
+            id,
+            attempt,
+            KEYGEN_ATTEMPTS,
+            attempt_started.elapsed().as_secs_f64(),
+            failure
+        ));
+        if attempt == KEYGEN_ATTEMPTS {
+            anyhow::bail!(
+                "ssh-keygen failed after {} attempts in {:.1}s (logged as [{}]): {}",
+                attempt,
+                started.elapsed().as_secs_f64(),
+                id,
+                failure
+            );
+        }
+        attempt += 1;
+        std::thread::sleep(KEYGEN_RETRY_DELAY);
+    }
+}
+
+/// Save key files to disk with proper permissions
+///
+/// All three files are written in full before any of them replaces the
+/// current one, so a failure leaves the previous key working. A stale `.pub`,
+/// from neither the current nor the new key, is handled as `on_pub_conflict`
+/// says. With `owner` the files are given to that user before they replace
+/// anything. Notices about a stale `.pub` and retries of ssh-keygen are
+/// reported to `log` line by line.
+pub fn save_key_files(
+    key_path: &Path,
+    key_content: &str,
+    cert_content: &str,
+    on_pub_conflict: PubConflict,
+    owner: Option<Owner>,
+    log: &dyn Fn(&str),
+) -> Result<SavedFiles> {
+    let files = SavedFiles::for_key(key_path);
+
+    // Private key with 600 permissions
+    let key = Staged::write(&files.key, key_content.as_bytes(), 0o600, owner)
+        .context("Failed to write private key")?;
+
+    let cert = Staged::write(&files.cert, cert_content.as_bytes(), 0o644, owner)
+        .context("Failed to write certificate")?;
+
+    // Generate public key from the staged private key using ssh-keygen
+    let stdout = ssh_keygen(&["-y".as_ref(), "-f".as_ref(), key.tmp.as_os_str()], log)
+        .context("Failed to generate public key with ssh-keygen")?;
+
+    let public_key = public_key_line(&stdout)?;
+    let replace_public = match stale_public_key(&files.public, &files.cert, &public_key) {
+        Some(old) => match on_pub_conflict {
+            PubConflict::Overwrite => {
+                log(&format!(
+                    "Replacing public key {}, it belonged to another key ({}) than the one being replaced",
+                    files.public.display(),
+                    old
+                ));
+                true
+            }
+            PubConflict::Keep => {
+                log(&format!(
+                    "Keeping public key {}, which belongs to another key ({}) than the new one",
+                    files.public.display(),
+                    old
+                ));
+                false
+            }
+            PubConflict::Error => anyhow::bail!(
+                "Public key {} belongs to another key ({}) than the current or new one; \
+                 nothing was replaced",
+                files.public.display(),
+                old
+            ),
+        },
+        None => true,
+    };
+    let public = if replace_public {
+        Some(
+            Staged::write(&files.public, public_key.as_bytes(), 0o644, owner)
+                .context("Failed to write public key")?,
+        )
+    } else {
+        None
+    };
+
+    for staged in [Some(key), Some(cert), public].into_iter().flatten() {
+        staged.commit()?;
+    }
+    Ok(files)
+}
+
+/// Fail if the umask lets others write files this run creates
+///
+/// The files written here get explicit modes, which the umask can only
+/// narrow, but anything else created in the output directory follows it.
+pub fn check_umask() -> Result<()> {
+    // SAFETY: umask cannot fail; the previous mask is put back right away
+    let mask = unsafe {
+        let mask = libc::umask(0o077);
+        libc::umask(mask);
+        mask
+    };
+    if mask & 0o022 != 0o022 {
+        anyhow::bail!(
+            "umask {:04o} lets group or others write new files; set it to 022 or stricter",
+            mask
+        );
+    }
+    Ok(())
+}
+
+/// Check the written files are no more permissive than the modes they were
+/// given: private ones (600) nothing for group and others, the rest (644) no
+/// write access for them
+///
+/// Some filesystems, e.g. FAT or exFAT mounts, ignore the requested modes
+/// and report their own.
+pub fn check_permissions(private: &[&Path], public: &[&Path]) -> Result<()> {
+    let checks = private
+        .iter()
+        .map(|path| (path, 0o077, 0o600))
+        .chain(public.iter().map(|path| (path, 0o022, 0o644)));
+    for (path, forbidden, wanted) in checks {
+        let mode = fs::metadata(path)
+            .with_context(|| format!("Failed to check permissions of {}", path.display()))?
+            .permissions()
+            .mode()
+            & 0o777;
+        if mode & forbidden != 0 {
+            anyhow::bail!(
+                "{} has mode {:03o} instead of {:03o}; the filesystem does not seem to \
+                 support Unix permissions. Move the files to one that does",
+                path.display(),
+                mode,
+                wanted
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Write the private key as unencrypted PKCS#8 DER, e.g. for a PKCS#11 soft token
+///
+/// ssh-keygen converts a 600 copy next to `dest`, so the key on disk is not
+/// touched. The result is parsed back before it is written, owned by `owner`
+/// if given.
+pub fn export_pkcs8(key_content: &str, dest: &Path, owner: Option<Owner>) -> Result<()> {
+    let copy = Staged::write(dest, key_content.as_bytes(), 0o600, None)
+        .context("Failed to write key for conversion")?;
+    let output = std::process::Command::new("ssh-keygen")
+        .args(["-p", "-m", "PKCS8", "-N", "", "-P", "", "-q", "-f"])
+        .arg(&copy.tmp)
+        .output()
+        .context("Failed to convert private key with ssh-keygen")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "ssh-keygen failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let pem = fs::read_to_string(&copy.tmp).context("Failed to read converted key")?;
+    drop(copy);
+
+    // ssh-keygen succeeds but keeps the OpenSSH format for key types it
+    // cannot export, e.g. ed25519 in some OpenSSH versions
+    let (_, der) = pkcs8::SecretDocument::from_pem(&pem)
+        .ok()
+        .filter(|(label, _)| *label == "PRIVATE KEY")
+        .context("ssh-keygen could not convert this key type to PKCS#8")?;
+    pkcs8::PrivateKeyInfo::try_from(der.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Converted key is not valid PKCS#8: {}", e))?;
+
+    Staged::write(dest, der.as_bytes(), 0o600, owner)?.commit()
+}
+
+/// Get certificate validity information, reporting ssh-keygen retries to `log`
+pub fn get_cert_validity(cert_path: &Path, log: &dyn Fn(&str)) -> Result<String> {
+    let stdout = ssh_keygen(&["-L".as_ref(), "-f".as_ref(), cert_path.as_os_str()], log)
+        .context("Failed to read certificate with ssh-keygen")?;
+
+    let output_str = String::from_utf8_lossy(&stdout);
+    for line in output_str.lines() {
+        if line.trim().starts_with("Valid:") {
+            return Ok(line.trim().to_string());
+        }
+    }
+
+    Ok("Valid: unknown".to_string())
+}
+
+/// End of the validity window from a `Valid: from X to Y` line
+pub fn valid_until(validity: &str) -> &str {
+    match validity.split_once(" to ") {
+        Some((_, until)) => until,
+        None => validity.trim_start_matches("Valid:").trim(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn failed_staged_write_leaves_old_key() {
+        let dir = std::env::temp_dir().join(format!("sshproxy-files-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = SavedFiles::for_key(&dir.join("nersc"));
+        fs::write(&files.key, "old private key\n").unwrap();
+        fs::write(&files.cert, "old certificate\n").unwrap();
+
+        // The certificate cannot be staged where a directory is in the way
+        let blocker = PathBuf::from(format!(
+            "{}.tmp.{}",
+            files.cert.display(),
+            std::process::id()
+        ));
+        fs::create_dir(&blocker).unwrap();
+
+        let err = save_key_files(
+            &files.key,
+            "new private key\n",
+            "new certificate\n",
+            PubConflict::Overwrite,
+            None,
+            &|_| {},
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Failed to write certificate"),
+            "{:#}",
+            err
+        );
+
+        assert_eq!(fs::read(&files.key).unwrap(), b"old private key\n");
+        assert_eq!(fs::read(&files.cert).unwrap(), b"old certificate\n");
+        // The staged private key was cleaned up
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 3, "{:?}", names);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn staged_file_is_owned_before_commit() {
+        // Only root can give files away
+        if !crate::owner::is_root() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("sshproxy-owner-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("nersc");
+        let owner = Owner {
+            uid: 65534,
+            gid: 65534,
+        };
+
+        let staged = Staged::write(&dest, b"key\n", 0o600, Some(owner)).unwrap();
+        let meta = fs::metadata(&staged.tmp).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (65534, 65534));
+        staged.commit().unwrap();
+        assert_eq!(fs::metadata(&dest).unwrap().uid(), 65534);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+//! Client for NERSC's sshproxy service
+//!
+//! Stores the NERSC password and TOTP secret in the system credential store,
+//! requests a key pair from sshproxy and writes it where ssh finds it. The
+//! `sshproxy-rust` binary is a thin command line front end over this crate;
+//! embedders can supply the one-time code themselves through
+//! [`client::OtpSource::callback`].
+
+pub mod cert;
+pub mod client;
+pub mod credentials;
+pub mod error;
+pub mod files;
+pub mod owner;
+pub mod tls;
+pub mod totp;
@@ -0,0 +1,151 @@
+//! Details of the last fetch, kept next to the key as `<key>-meta.json`
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sshproxy_rust::files;
+use sshproxy_rust::owner::Owner;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Unix time the key was fetched
+    pub fetched_at: u64,
+
+    /// Base URL of the service the key came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    /// Scope the key was requested for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+
+    /// Profile selected for the fetch, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+/// Service, scope and profile a key is fetched from
+#[derive(Debug, Clone, Copy)]
+pub struct Source<'a> {
+    pub url: &'a str,
+    pub scope: &'a str,
+    pub profile: Option<&'a str>,
+}
+
+impl Metadata {
+    /// Whether the key was fetched from `source`
+    ///
+    /// Keys fetched before the source was recorded are assumed to match.
+    pub fn is_from(&self, source: &Source) -> bool {
+        match &self.url {
+            None => true,
+            Some(url) => {
+                url == source.url
+                    && self.scope.as_deref() == Some(source.scope)
+                    && self.profile.as_deref() == source.profile
+            }
+        }
+    }
+
+    /// Metadata file belonging to a key
+    pub fn path_for(key_path: &Path) -> PathBuf {
+        PathBuf::from(format!("{}-meta.json", key_path.display()))
+    }
+
+    /// Metadata of the key on disk, if any was recorded
+    pub fn read(key_path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(Self::path_for(key_path)).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    /// Record the metadata next to the key, owned by `owner` if given
+    pub fn write(&self, key_path: &Path, owner: Option<Owner>) -> Result<()> {
+        let path = Self::path_for(key_path);
+        let text = serde_json::to_string_pretty(self)? + "\n";
+        files::write_staged(&path, text.as_bytes(), 0o644, owner)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Seconds since the key on disk was fetched from `source`, if that is less
+/// than `age`
+pub fn fetched_within(key_path: &Path, source: &Source, age: u64, now: u64) -> Option<u64> {
+    if !key_path.exists() {
+        return None;
+    }
+    let metadata = Metadata::read(key_path).filter(|metadata| metadata.is_from(source))?;
+    let elapsed = now.saturating_sub(metadata.fetched_at);
+    (elapsed < age).then_some(elapsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: Source = Source {
+        url: "https://sshproxy.nersc.gov",
+        scope: "default",
+        profile: None,
+    };
+
+    fn metadata(url: Option<&str>, scope: &str, profile: Option<&str>) -> Metadata {
+        Metadata {
+            fetched_at: 1000,
+            url: url.map(String::from),
+            scope: Some(scope.to_string()),
+            profile: profile.map(String::from),
+        }
+    }
+
+    #[test]
+    fn source_mismatch_is_not_from_source() {
+        assert!(metadata(Some(SOURCE.url), "default", None).is_from(&SOURCE));
+        assert!(!metadata(Some("https://other.example.org"), "default", None).is_from(&SOURCE));
+        assert!(!metadata(Some(SOURCE.url), "project", None).is_from(&SOURCE));
+        assert!(!metadata(Some(SOURCE.url), "default", Some("perlmutter")).is_from(&SOURCE));
+        // Recorded before url, scope and profile were
+        assert!(metadata(None, "", None).is_from(&SOURCE));
+    }
+
+    #[test]
+    fn fetched_within_skips_other_source() {
+        let dir = std::env::temp_dir().join(format!("sshproxy-meta-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("nersc");
+        fs::write(&key, "key").unwrap();
+
+        metadata(Some(SOURCE.url), "default", None)
+            .write(&key, None)
+            .unwrap();
+        assert_eq!(fetched_within(&key, &SOURCE, 60, 1010), Some(10));
+        assert_eq!(fetched_within(&key, &SOURCE, 5, 1010), None);
+
+        metadata(Some(SOURCE.url), "default", Some("perlmutter"))
+            .write(&key, None)
+            .unwrap();
+        assert_eq!(fetched_within(&key, &SOURCE, 60, 1010), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_replaces_planted_symlink() {
+        let dir = std::env::temp_dir().join(format!("sshproxy-meta-link-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("nersc");
+        let target = dir.join("target");
+        fs::write(&target, "untouched\n").unwrap();
+        std::os::unix::fs::symlink(&target, Metadata::path_for(&key)).unwrap();
+
+        metadata(Some(SOURCE.url), "default", None)
+            .write(&key, None)
+            .unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "untouched\n");
+        assert!(!Metadata::path_for(&key).is_symlink());
+        assert_eq!(Metadata::read(&key).unwrap().fetched_at, 1000);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+//! Moving keys from a legacy location to the current naming scheme
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sshproxy_rust::files::{cert_path_for, pub_path_for};
+
+use crate::ssh_config;
+
+/// Private key, certificate and public key belonging to a key path
+fn key_files(key_path: &Path) -> [PathBuf; 3] {
+    [
+        key_path.to_path_buf(),
+        cert_path_for(key_path),
+        pub_path_for(key_path),
+    ]
+}
+
+/// Backup location used before overwriting a file
+fn backup_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.bak", path.display()))
+}
+
+/// Rename, falling back to copy and remove across filesystems
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)
+            .with_context(|| format!("Failed to copy {} to {}", from.display(), to.display()))?;
+        fs::remove_file(from).with_context(|| format!("Failed to remove {}", from.display()))?;
+    }
+    Ok(())
+}
+
+/// Move the key files at `from` to `key_path` and update ssh config references
+pub fn run(from: &Path, key_path: &Path, home: &Path, dry_run: bool) -> Result<()> {
+    let renames: Vec<(PathBuf, PathBuf)> = key_files(from)
+        .into_iter()
+        .zip(key_files(key_path))
+        .filter(|(old, new)| old != new && old.exists())
+        .collect();
+
+    if renames.is_empty() {
+        if key_path.exists() {
+            println!(
+                "Nothing to migrate, keys are already at {}",
+                key_path.display()
+            );
+            return Ok(());
+        }
+        anyhow::bail!("No key found at {}", from.display());
+    }
+
+    for (old, new) in &renames {
+        if new.exists() {
+            let backup = backup_path(new);
+            println!("Back up {} to {}", new.display(), backup.display());
+            if !dry_run {
+                fs::copy(new, &backup)
+                    .with_context(|| format!("Failed to back up {}", new.display()))?;
+            }
+        }
+        println!("Move {} to {}", old.display(), new.display());
+        if !dry_run {
+            move_file(old, new)?;
+        }
+    }
+
+    let config_path = ssh_config::config_path(home);
+    if let Ok(text) = fs::read_to_string(&config_path) {
+        let (updated, changed) = ssh_config::rewrite_key_paths(&text, &renames, home);
+        if changed > 0 {
+            println!(
+                "Update {} reference(s) in {}",
+                changed,
+                config_path.display()
+            );
+            if !dry_run {
+                fs::copy(&config_path, backup_path(&config_path))
+                    .context("Failed to back up ssh config")?;
+                fs::write(&config_path, updated).context("Failed to update ssh config")?;
+            }
+        }
+    }
+
+    if dry_run {
+        println!("Dry run, nothing was changed.");
+    } else {
+        println!("Migration complete.");
+    }
+    Ok(())
+}
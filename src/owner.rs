@@ -0,0 +1,68 @@
+//! Changing ownership of written files when provisioning for another user
+
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::fs::File;
+use std::path::Path;
+
+/// Numeric owner resolved from a `user[:group]` spec
+#[derive(Debug, Clone, Copy)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Owner {
+    /// Resolve `user` or `user:group`; the user's primary group is the default
+    pub fn resolve(spec: &str) -> Result<Self> {
+        let (user, group) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec, None),
+        };
+
+        let name = CString::new(user).context("Invalid user name")?;
+        // SAFETY: name is a valid C string; the returned record is copied out immediately
+        let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+        if passwd.is_null() {
+            anyhow::bail!("User '{}' does not exist", user);
+        }
+        let (uid, mut gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+
+        if let Some(group) = group {
+            let name = CString::new(group).context("Invalid group name")?;
+            // SAFETY: as above
+            let grp = unsafe { libc::getgrnam(name.as_ptr()) };
+            if grp.is_null() {
+                anyhow::bail!("Group '{}' does not exist", group);
+            }
+            gid = unsafe { (*grp).gr_gid };
+        }
+
+        Ok(Owner { uid, gid })
+    }
+
+    /// Change ownership of each file, of a symlink itself rather than its target
+    ///
+    /// Files written into a directory the new owner controls should be
+    /// given away through their descriptor before they are moved into place
+    /// instead, see [`Owner::apply_to_file`].
+    pub fn apply(&self, paths: &[&Path]) -> Result<()> {
+        for path in paths {
+            std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid))
+                .with_context(|| format!("Failed to change owner of {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Change ownership of an open file
+    pub fn apply_to_file(&self, file: &File, path: &Path) -> Result<()> {
+        std::os::unix::fs::fchown(file, Some(self.uid), Some(self.gid))
+            .with_context(|| format!("Failed to change owner of {}", path.display()))
+    }
+}
+
+/// Whether the process runs with root privileges
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
@@ -0,0 +1,82 @@
+//! Interactive prompts that give up after `--prompt-timeout`
+
+use anyhow::Result;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// How long a prompt waits for input, unlimited unless set
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Make every later prompt give up after `timeout`
+pub fn set_timeout(timeout: Duration) {
+    let _ = TIMEOUT.set(timeout);
+}
+
+/// Settings of the controlling terminal, to put back after an abandoned prompt
+struct TerminalState {
+    tty: File,
+    termios: libc::termios,
+}
+
+impl TerminalState {
+    /// Current settings, `None` without a controlling terminal
+    fn save() -> Option<Self> {
+        let tty = File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .ok()?;
+        // SAFETY: termios is plain data and filled in by tcgetattr on success
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut termios) } != 0 {
+            return None;
+        }
+        Some(TerminalState { tty, termios })
+    }
+
+    /// Turn echo back on if a password prompt had turned it off
+    fn restore(&self) {
+        // SAFETY: the descriptor is open and termios came from tcgetattr
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.termios) };
+    }
+}
+
+/// Run a blocking prompt, failing if it gets no input within the timeout
+///
+/// Without a timeout the prompt runs as is. Otherwise it runs on its own
+/// thread, which is left blocked on the terminal when the timeout passes;
+/// the process exits soon after with the error returned here.
+pub fn read<T, F>(what: &str, prompt: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    let Some(&timeout) = TIMEOUT.get() else {
+        return Ok(prompt()?);
+    };
+
+    let terminal = TerminalState::save();
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(prompt());
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            if let Some(terminal) = terminal {
+                terminal.restore();
+            }
+            // The prompt line was left without its newline
+            eprintln!();
+            anyhow::bail!(
+                "No {} entered within {}s (--prompt-timeout)",
+                what,
+                timeout.as_secs()
+            )
+        }
+    }
+}
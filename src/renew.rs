@@ -0,0 +1,308 @@
+//! Scheduled renewal: a systemd user timer on Linux, a launchd agent on macOS
+//!
+//! The job runs the fetch every hour with a `--min-valid` of two hours, so the
+//! current key is reused until it is about to expire and replaced only then.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sshproxy_rust::totp::SecretEncoding;
+
+/// How often the job runs, in seconds; the systemd timer says `hourly`
+const INTERVAL: u64 = 3600;
+
+/// `--min-valid` passed to the job: two runs' worth, so one failure is survivable
+const MIN_VALID: u64 = 2 * INTERVAL;
+
+/// What `install-renew` does with the generated files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Preview,
+    Install,
+    Uninstall,
+}
+
+/// Settings of the installing run the job has to repeat
+#[derive(Debug)]
+pub struct Job<'a> {
+    pub username: &'a str,
+    /// Profile in use, whether from `--profile` or `SSHPROXY_PROFILE`
+    pub profile: Option<&'a str>,
+    /// `--output` template, rendered again by each run
+    pub output: Option<&'a str>,
+    /// `--secret-encoding` if given; the config file is read by the job anyway
+    pub secret_encoding: Option<SecretEncoding>,
+}
+
+/// Command line the job runs
+fn job_args(job: &Job) -> Result<Vec<String>> {
+    let exe = std::env::current_exe().context("Could not determine the path of this binary")?;
+    let exe = exe.canonicalize().unwrap_or(exe);
+    let mut args = vec![
+        exe.display().to_string(),
+        job.username.to_string(),
+        "--min-valid".to_string(),
+        MIN_VALID.to_string(),
+    ];
+    if let Some(profile) = job.profile {
+        args.extend(["--profile".to_string(), profile.to_string()]);
+    }
+    if let Some(output) = job.output {
+        args.extend(["--output".to_string(), output.to_string()]);
+    }
+    if let Some(encoding) = job.secret_encoding.and_then(|e| e.to_possible_value()) {
+        args.extend([
+            "--secret-encoding".to_string(),
+            encoding.get_name().to_string(),
+        ]);
+    }
+    Ok(args)
+}
+
+/// Run a service manager command, failing with its stderr
+fn run_command(program: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn write_files(files: &[(PathBuf, String)]) -> Result<()> {
+    for (path, content) in files {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        fs::write(path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+fn remove_files(files: &[(PathBuf, String)]) -> Result<()> {
+    for (path, _) in files {
+        match fs::remove_file(path) {
+            Ok(()) => println!("Removed {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn preview(files: &[(PathBuf, String)]) {
+    for (path, content) in files {
+        println!("# {}", path.display());
+        print!("{}", content);
+        println!();
+    }
+    println!("Nothing was installed; run again with --install to install these files.");
+}
+
+/// Quote a word for a systemd `ExecStart=` line, where `%` starts a specifier
+/// and `$` an environment variable
+#[cfg(target_os = "linux")]
+fn systemd_quote(word: &str) -> String {
+    let escaped = word
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(target_os = "linux")]
+fn unit_files(home: &Path, job: &Job) -> Result<Vec<(PathBuf, String)>> {
+    let dir = home.join(".config").join("systemd").join("user");
+    let exec: Vec<String> = job_args(job)?
+        .iter()
+        .map(|word| systemd_quote(word))
+        .collect();
+    let service = format!(
+        "[Unit]\n\
+         Description=Renew the NERSC ssh key before it expires\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={}\n",
+        exec.join(" ")
+    );
+    let timer = "[Unit]\n\
+         Description=Renew the NERSC ssh key before it expires\n\
+         \n\
+         [Timer]\n\
+         OnCalendar=hourly\n\
+         RandomizedDelaySec=5min\n\
+         Persistent=true\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n"
+        .to_string();
+    Ok(vec![
+        (dir.join("sshproxy-renew.service"), service),
+        (dir.join("sshproxy-renew.timer"), timer),
+    ])
+}
+
+/// Preview, install or remove the systemd user timer
+#[cfg(target_os = "linux")]
+pub fn run(home: &Path, job: &Job, action: Action) -> Result<()> {
+    let files = unit_files(home, job)?;
+    match action {
+        Action::Preview => preview(&files),
+        Action::Install => {
+            write_files(&files)?;
+            run_command("systemctl", &["--user", "daemon-reload"])?;
+            run_command(
+                "systemctl",
+                &["--user", "enable", "--now", "sshproxy-renew.timer"],
+            )?;
+            println!("Enabled sshproxy-renew.timer");
+        }
+        Action::Uninstall => {
+            // Already disabled or never installed is fine
+            let _ = run_command(
+                "systemctl",
+                &["--user", "disable", "--now", "sshproxy-renew.timer"],
+            );
+            remove_files(&files)?;
+            run_command("systemctl", &["--user", "daemon-reload"])?;
+        }
+    }
+    Ok(())
+}
+
+/// Label of the launchd agent, also its file name
+#[cfg(target_os = "macos")]
+const LABEL: &str = "gov.nersc.sshproxy-renew";
+
+#[cfg(target_os = "macos")]
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(target_os = "macos")]
+fn agent_files(home: &Path, job: &Job) -> Result<Vec<(PathBuf, String)>> {
+    let path = home
+        .join("Library")
+        .join("LaunchAgents")
+        .join(format!("{}.plist", LABEL));
+    let arguments: String = job_args(job)?
+        .iter()
+        .map(|word| format!("        <string>{}</string>\n", xml_escape(word)))
+        .collect();
+    let plist = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n    \
+             <key>Label</key>\n    \
+             <string>{}</string>\n    \
+             <key>ProgramArguments</key>\n    \
+             <array>\n{}    </array>\n    \
+             <key>StartInterval</key>\n    \
+             <integer>{}</integer>\n    \
+             <key>RunAtLoad</key>\n    \
+             <true/>\n\
+         </dict>\n\
+         </plist>\n",
+        LABEL, arguments, INTERVAL
+    );
+    Ok(vec![(path, plist)])
+}
+
+/// Preview, install or remove the launchd agent
+#[cfg(target_os = "macos")]
+pub fn run(home: &Path, job: &Job, action: Action) -> Result<()> {
+    let files = agent_files(home, job)?;
+    // SAFETY: getuid has no preconditions and cannot fail
+    let domain = format!("gui/{}", unsafe { libc::getuid() });
+    let plist = files[0].0.display().to_string();
+    match action {
+        Action::Preview => preview(&files),
+        Action::Install => {
+            write_files(&files)?;
+            // Replace an agent loaded by an earlier install
+            let _ = run_command("launchctl", &["bootout", &domain, &plist]);
+            run_command("launchctl", &["bootstrap", &domain, &plist])?;
+            println!("Loaded {}", LABEL);
+        }
+        Action::Uninstall => {
+            let _ = run_command("launchctl", &["bootout", &domain, &plist]);
+            remove_files(&files)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_repeats_profile_output_and_encoding() {
+        let job = Job {
+            username: "alice",
+            profile: Some("perlmutter"),
+            output: Some("/keys/{scope}"),
+            secret_encoding: Some(SecretEncoding::Hex),
+        };
+        assert_eq!(
+            job_args(&job).unwrap()[1..],
+            [
+                "alice",
+                "--min-valid",
+                "7200",
+                "--profile",
+                "perlmutter",
+                "--output",
+                "/keys/{scope}",
+                "--secret-encoding",
+                "hex",
+            ]
+        );
+
+        // ExecStart expands specifiers and variables, so both are escaped
+        #[cfg(target_os = "linux")]
+        {
+            let job = Job {
+                username: "alice",
+                profile: Some("team$HOME"),
+                output: Some("/keys/${USER}/100%/\"x\""),
+                secret_encoding: None,
+            };
+            let exec: Vec<String> = job_args(&job).unwrap()[1..]
+                .iter()
+                .map(|word| systemd_quote(word))
+                .collect();
+            assert_eq!(
+                exec,
+                [
+                    "\"alice\"",
+                    "\"--min-valid\"",
+                    "\"7200\"",
+                    "\"--profile\"",
+                    "\"team$$HOME\"",
+                    "\"--output\"",
+                    "\"/keys/$${USER}/100%%/\\\"x\\\"\"",
+                ]
+            );
+        }
+    }
+}
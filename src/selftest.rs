@@ -0,0 +1,144 @@
+//! Offline checks of config parsing, TOTP, certificate handling and paths
+//!
+//! Runs against built-in inputs only: no network, no stored credentials and
+//! nothing written to disk.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use sshproxy_rust::cert::{self, CaBundle, CertValidity};
+use sshproxy_rust::files;
+use sshproxy_rust::totp::{self, SecretEncoding, TotpParams};
+
+use crate::config::{self, Config};
+use crate::metadata::Metadata;
+
+/// Certificate signed by `SELFTEST_CA`, valid 2020-01-01 to 2099-12-31
+const SELFTEST_CERT: &str = include_str!("../fixtures/selftest-cert.pub");
+
+/// CA that signed `SELFTEST_CERT`
+const SELFTEST_CA: &str = include_str!("../fixtures/selftest-ca.pub");
+
+/// RFC 6238 appendix B seed for SHA-1, base32
+const RFC6238_SHA1_SEED: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+/// RFC 6238 appendix B seed for SHA-256, base32
+const RFC6238_SHA256_SEED: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZA";
+
+/// One named check
+type Check = (&'static str, fn() -> Result<()>);
+
+fn expect<T: PartialEq + std::fmt::Debug>(what: &str, actual: T, expected: T) -> Result<()> {
+    if actual != expected {
+        anyhow::bail!("{}: expected {:?}, got {:?}", what, expected, actual);
+    }
+    Ok(())
+}
+
+fn check_config() -> Result<()> {
+    let config = Config::parse("url = \"https://proxy.example.org/\"\nscope = \"test\"\n")?;
+    let url = config.url.as_deref().context("url not parsed")?;
+    let scope = config.scope.as_deref().context("scope not parsed")?;
+    expect(
+        "endpoint",
+        config::endpoint_for(url, scope)?.as_str(),
+        "https://proxy.example.org/create_pair/test/",
+    )?;
+
+    if config::endpoint_for("http://proxy.example.org", "test").is_ok() {
+        anyhow::bail!("plain http to a remote host was accepted");
+    }
+    if Config::parse("no_such_setting = 1").is_ok() {
+        anyhow::bail!("unknown setting was accepted");
+    }
+    Ok(())
+}
+
+fn check_totp() -> Result<()> {
+    let sha1 = TotpParams::from_stored(RFC6238_SHA1_SEED, SecretEncoding::Base32)?;
+    expect("SHA-1, 6 digits", sha1.code_at(59).as_str(), "287082")?;
+
+    let uri = format!("otpauth://totp/test?secret={}&digits=8", RFC6238_SHA1_SEED);
+    let sha1 = TotpParams::from_stored(&uri, SecretEncoding::Base32)?;
+    expect("SHA-1, 8 digits", sha1.code_at(59).as_str(), "94287082")?;
+    expect(
+        "SHA-1, 8 digits",
+        sha1.code_at(1111111109).as_str(),
+        "07081804",
+    )?;
+
+    let uri = format!(
+        "otpauth://totp/test?secret={}&digits=8&algorithm=SHA256",
+        RFC6238_SHA256_SEED
+    );
+    let sha256 = TotpParams::from_stored(&uri, SecretEncoding::Base32)?;
+    expect("SHA-256, 8 digits", sha256.code_at(59).as_str(), "46119246")?;
+
+    expect(
+        "grouping",
+        totp::group_code("287082", " ").as_str(),
+        "287 082",
+    )
+}
+
+fn check_certificate() -> Result<()> {
+    let cert = cert::parse_certificate(SELFTEST_CERT)?;
+    expect(
+        "principals",
+        cert.valid_principals(),
+        &["selftest".to_string()][..],
+    )?;
+
+    let validity = CertValidity::of(&cert);
+    expect("valid after", validity.valid_after, 1577836800)?;
+    expect("valid before", validity.valid_before, 4102358400)?;
+
+    let bundle = CaBundle::parse(SELFTEST_CA)?;
+    let ca = bundle.verify(&cert)?;
+    expect("signing CA", ca.comment(), "selftest-ca")
+}
+
+fn check_paths() -> Result<()> {
+    let key = Path::new("/home/user/.ssh/nersc");
+    expect(
+        "certificate",
+        files::cert_path_for(key),
+        PathBuf::from("/home/user/.ssh/nersc-cert.pub"),
+    )?;
+    expect(
+        "public key",
+        files::pub_path_for(key),
+        PathBuf::from("/home/user/.ssh/nersc.pub"),
+    )?;
+    expect(
+        "metadata",
+        Metadata::path_for(key),
+        PathBuf::from("/home/user/.ssh/nersc-meta.json"),
+    )
+}
+
+/// Run every check, printing one PASS/FAIL line each
+pub fn run() -> Result<()> {
+    let checks: [Check; 4] = [
+        ("config resolution", check_config),
+        ("TOTP test vectors", check_totp),
+        ("certificate parsing", check_certificate),
+        ("path derivation", check_paths),
+    ];
+
+    let mut failed = 0;
+    for (name, check) in checks {
+        match check() {
+            Ok(()) => println!("PASS  {}", name),
+            Err(e) => {
+                println!("FAIL  {}: {:#}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} self-test checks failed", failed, checks.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! Handing the key to a local helper over a Unix socket, for `--output-socket`
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::Metadata;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use sshproxy_rust::client::KeyBundle;
+
+/// How long the helper gets to accept the key and reply
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fail unless only we, or root and a group of ours, can have made the socket
+fn check_access(path: &Path, meta: &Metadata) -> Result<()> {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let euid = unsafe { libc::geteuid() };
+    let mode = meta.mode() & 0o777;
+    if meta.uid() == euid {
+        if mode & 0o077 != 0 {
+            anyhow::bail!(
+                "Socket {} has mode {:o}, it must be 600 so only you can connect",
+                path.display(),
+                mode
+            );
+        }
+        return Ok(());
+    }
+    if meta.uid() != 0 {
+        anyhow::bail!(
+            "Socket {} is owned by uid {}, not by you (uid {}) or root",
+            path.display(),
+            meta.uid(),
+            euid
+        );
+    }
+    if mode & 0o007 != 0 || !in_group(meta.gid()) {
+        anyhow::bail!(
+            "Socket {} is owned by root with mode {:o} and gid {}; a root-owned socket \
+             must have mode 660 or tighter and a group you are in",
+            path.display(),
+            mode,
+            meta.gid()
+        );
+    }
+    Ok(())
+}
+
+/// Whether `gid` is our effective or a supplementary group
+fn in_group(gid: u32) -> bool {
+    // SAFETY: getegid has no preconditions and cannot fail
+    if unsafe { libc::getegid() } == gid {
+        return true;
+    }
+    // SAFETY: a size of 0 only asks for the number of groups
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count <= 0 {
+        return false;
+    }
+    let mut groups = vec![0 as libc::gid_t; count as usize];
+    // SAFETY: the buffer holds `count` entries
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    count > 0 && groups[..count as usize].contains(&gid)
+}
+
+/// The one JSON line sent to the helper
+#[derive(Serialize)]
+struct Message<'a> {
+    username: &'a str,
+    #[serde(flatten)]
+    bundle: &'a KeyBundle,
+    /// Unix time the certificate expires
+    valid_before: u64,
+}
+
+/// Send the key bundle to the helper listening on `path` and wait for its `ok`
+///
+/// The socket has to be ours and closed to everyone else (mode 600), so the
+/// private key cannot end up with a helper run by another user. A helper
+/// running as root may instead keep the socket owned by root and open it to
+/// a group we are in (mode 660), since root can read the key anyway.
+pub fn send(path: &Path, username: &str, bundle: &KeyBundle, valid_before: u64) -> Result<()> {
+    let meta = path
+        .metadata()
+        .with_context(|| format!("Cannot use socket {}", path.display()))?;
+    if !meta.file_type().is_socket() {
+        anyhow::bail!("{} is not a socket", path.display());
+    }
+    check_access(path, &meta)?;
+
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to socket {}", path.display()))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let message = Message {
+        username,
+        bundle,
+        valid_before,
+    };
+    let mut line = serde_json::to_string(&message)?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .and_then(|()| stream.shutdown(std::net::Shutdown::Write))
+        .with_context(|| format!("Failed to send the key to {}", path.display()))?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut reply)
+        .with_context(|| format!("No reply from the helper at {}", path.display()))?;
+    match reply.trim() {
+        "ok" => Ok(()),
+        "" => anyhow::bail!(
+            "The helper at {} closed the connection without accepting the key",
+            path.display()
+        ),
+        error => anyhow::bail!(
+            "The helper at {} did not accept the key: {}",
+            path.display(),
+            error
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn own_socket_must_be_private() {
+        let dir = std::env::temp_dir().join(format!("sshproxy-socket-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("helper.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        check_access(&path, &path.metadata().unwrap()).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o660)).unwrap();
+        let err = check_access(&path, &path.metadata().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("it must be 600"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
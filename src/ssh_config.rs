@@ -0,0 +1,82 @@
+//! Editing key references in `~/.ssh/config`
+
+use std::path::{Path, PathBuf};
+
+/// Directives whose value is a path to key material
+const PATH_DIRECTIVES: &[&str] = &["identityfile", "certificatefile"];
+
+/// Location of the user's ssh client configuration
+pub fn config_path(home: &Path) -> PathBuf {
+    home.join(".ssh").join("config")
+}
+
+/// Expand a leading `~/` against the home directory
+fn expand_home(value: &str, home: &Path) -> PathBuf {
+    match value.strip_prefix("~/") {
+        Some(rest) => home.join(rest),
+        None => PathBuf::from(value),
+    }
+}
+
+/// Write a path the way users usually do in ssh config, with `~/` for home
+fn abbreviate_home(path: &Path, home: &Path) -> String {
+    match path.strip_prefix(home) {
+        Ok(rest) => format!("~/{}", rest.display()),
+        Err(_) => path.display().to_string(),
+    }
+}
+
+/// Rewrite `IdentityFile`/`CertificateFile` values that point at a renamed file
+///
+/// Returns the new text and the number of lines changed.
+pub fn rewrite_key_paths(
+    text: &str,
+    renames: &[(PathBuf, PathBuf)],
+    home: &Path,
+) -> (String, usize) {
+    let mut changed = 0;
+    let mut out = String::with_capacity(text.len());
+
+    for line in text.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[body.len()..];
+        let indent = &body[..body.len() - body.trim_start().len()];
+
+        // Directives are `Keyword value` or `Keyword=value`
+        let mut parts = body
+            .trim_start()
+            .splitn(2, |c: char| c.is_whitespace() || c == '=');
+        let keyword = parts.next().unwrap_or("");
+        let value = parts
+            .next()
+            .map(|v| {
+                v.trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+                    .trim()
+            })
+            .unwrap_or("");
+
+        let renamed = PATH_DIRECTIVES
+            .contains(&keyword.to_ascii_lowercase().as_str())
+            .then(|| {
+                let current = expand_home(value.trim_matches('"'), home);
+                renames.iter().find(|(old, _)| *old == current)
+            })
+            .flatten();
+
+        match renamed {
+            Some((_, new)) => {
+                out.push_str(&format!(
+                    "{}{} {}{}",
+                    indent,
+                    keyword,
+                    abbreviate_home(new, home),
+                    ending
+                ));
+                changed += 1;
+            }
+            None => out.push_str(line),
+        }
+    }
+
+    (out, changed)
+}
@@ -0,0 +1,38 @@
+//! Minimal `{placeholder}` templates used for user-facing formats
+
+use anyhow::Result;
+
+/// Check that every `{name}` in the template is one of `allowed`
+pub fn check_placeholders(template: &str, allowed: &[&str]) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("Unclosed '{{' in template \"{}\"", template))?;
+        let name = &after[..end];
+        if !allowed.contains(&name) {
+            anyhow::bail!(
+                "Unknown placeholder {{{}}} in template \"{}\" (expected one of: {})",
+                name,
+                template,
+                allowed
+                    .iter()
+                    .map(|a| format!("{{{}}}", a))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        rest = &after[end + 1..];
+    }
+    Ok(())
+}
+
+/// Substitute each `{name}` with its value
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |out, (name, value)| {
+            out.replace(&format!("{{{}}}", name), value)
+        })
+}
@@ -0,0 +1,31 @@
+//! Phase durations printed to stderr with `--timings`
+
+use std::time::Instant;
+
+pub struct Timings {
+    enabled: bool,
+    since: Instant,
+}
+
+impl Timings {
+    pub fn new(enabled: bool) -> Self {
+        Timings {
+            enabled,
+            since: Instant::now(),
+        }
+    }
+
+    /// Report the time spent since the previous phase ended
+    pub fn phase(&mut self, name: &str) {
+        if self.enabled {
+            let elapsed = self.since.elapsed();
+            eprintln!(
+                "{}timing: {:<22} {:>9.1} ms",
+                crate::tag(),
+                name,
+                elapsed.as_secs_f64() * 1000.0
+            );
+        }
+        self.since = Instant::now();
+    }
+}
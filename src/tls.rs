@@ -0,0 +1,251 @@
+//! TLS configuration with the server's public key pinned, and the minimum
+//! protocol version
+
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    AlertDescription, CertificateError, ClientConfig, DigitallySignedStruct, OtherError,
+    PeerIncompatible, RootCertStore, SupportedProtocolVersion,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::Arc;
+use x509_cert::der::{Decode, Encode};
+
+/// Protocol versions offered with a TLS 1.3 minimum
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// Oldest TLS version accepted for the connection to the sshproxy service
+///
+/// rustls never speaks TLS 1.0 or 1.1, so 1.2 is the lowest there is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    #[value(name = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// The same minimum for reqwest's own TLS setup
+    pub fn reqwest(self) -> reqwest::tls::Version {
+        match self {
+            TlsVersion::Tls12 => reqwest::tls::Version::TLS_1_2,
+            TlsVersion::Tls13 => reqwest::tls::Version::TLS_1_3,
+        }
+    }
+
+    /// rustls protocol versions at or above the minimum
+    fn rustls(self) -> &'static [&'static SupportedProtocolVersion] {
+        match self {
+            TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsVersion::Tls12 => f.write_str("TLS 1.2"),
+            TlsVersion::Tls13 => f.write_str("TLS 1.3"),
+        }
+    }
+}
+
+/// SHA-256 of a certificate's DER-encoded SubjectPublicKeyInfo
+pub fn spki_sha256(cert_der: &[u8]) -> Result<[u8; 32]> {
+    let cert =
+        x509_cert::Certificate::from_der(cert_der).context("Failed to parse TLS certificate")?;
+    let spki = cert
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .context("Failed to encode certificate public key")?;
+    Ok(Sha256::digest(spki).into())
+}
+
+/// Pin in the `sha256/<base64>` form used in the docs and error messages
+pub fn format_pin(pin: &[u8; 32]) -> String {
+    format!("sha256/{}", data_encoding::BASE64.encode(pin))
+}
+
+/// Parse a pin given as base64, optionally prefixed with `sha256/`
+pub fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let encoded = pin.trim().strip_prefix("sha256/").unwrap_or(pin.trim());
+    let bytes = data_encoding::BASE64
+        .decode(encoded.as_bytes())
+        .context("tls_pin is not valid base64")?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        anyhow::anyhow!(
+            "tls_pin must be a SHA-256 hash (32 bytes), got {} bytes",
+            bytes.len()
+        )
+    })
+}
+
+/// The server presented a public key other than the pinned one
+#[derive(Debug)]
+pub struct PinMismatch {
+    pub actual: [u8; 32],
+    pub expected: [u8; 32],
+}
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "server public key {} does not match tls_pin {}",
+            format_pin(&self.actual),
+            format_pin(&self.expected)
+        )
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// Find a pin mismatch behind a failed connection
+pub fn find_pin_mismatch<'a>(
+    err: &'a (dyn std::error::Error + 'static),
+) -> Option<&'a PinMismatch> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(rustls::Error::InvalidCertificate(CertificateError::Other(other))) =
+            err.downcast_ref::<rustls::Error>()
+        {
+            return other.0.downcast_ref::<PinMismatch>();
+        }
+        // io::Error::source skips the error it wraps, so look inside directly
+        source = match err.downcast_ref::<std::io::Error>() {
+            Some(io) => io.get_ref().map(|inner| inner as _),
+            None => err.source(),
+        };
+    }
+    None
+}
+
+/// Whether a failed connection is down to the server not offering a version
+/// at or above the minimum
+pub fn is_version_mismatch(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(
+            rustls::Error::AlertReceived(AlertDescription::ProtocolVersion)
+            | rustls::Error::PeerIncompatible(
+                PeerIncompatible::ServerDoesNotSupportTls12Or13
+                | PeerIncompatible::SupportedVersionsExtensionRequired
+                | PeerIncompatible::Tls12NotOffered
+                | PeerIncompatible::Tls12NotOfferedOrEnabled,
+            ),
+        ) = err.downcast_ref::<rustls::Error>()
+        {
+            return true;
+        }
+        source = match err.downcast_ref::<std::io::Error>() {
+            Some(io) => io.get_ref().map(|inner| inner as _),
+            None => err.source(),
+        };
+    }
+    false
+}
+
+/// Normal certificate verification, plus a check of the leaf's public key
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pin: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let actual = spki_sha256(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if actual != self.pin {
+            let mismatch = PinMismatch {
+                actual,
+                expected: self.pin,
+            };
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                OtherError(Arc::new(mismatch)),
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Client configuration trusting the system roots and only the pinned key
+///
+/// reqwest ignores its own minimum version for a preconfigured client, and
+/// does not set its ALPN protocols, so both are applied here. With `http2`
+/// the server may pick HTTP/2, otherwise HTTP/1.1 is used.
+pub fn pinned_config(pin: [u8; 32], min_version: TlsVersion, http2: bool) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    for cert in native.certs {
+        // Unusable system certificates are skipped, like other TLS clients do
+        let _ = roots.add(cert);
+    }
+    if roots.is_empty() {
+        anyhow::bail!("No usable system root certificates found");
+    }
+
+    let provider = Arc::new(ring::default_provider());
+    let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .context("Failed to set up TLS certificate verification")?;
+
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(min_version.rustls())
+        .context("Failed to set up TLS")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier { inner, pin }))
+        .with_no_client_auth();
+    if http2 {
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    Ok(config)
+}